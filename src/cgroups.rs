@@ -0,0 +1,271 @@
+use std::fs::{create_dir_all, remove_dir, write, OpenOptions};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+
+use crate::{CpuResources, MemoryResources, PidsResources, Resources};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CONTROLLERS: &[&str] = &["memory", "cpu", "pids"];
+
+/// A cgroup v2 hierarchy created for a single container.
+///
+/// `Fs` owns a plain directory under `/sys/fs/cgroup` that we create and
+/// tear down ourselves. `Systemd` instead owns a transient scope unit and
+/// lets systemd manage the underlying directory; we remember its path
+/// alongside the unit name, but `delete` only stops the unit and leaves
+/// removing the directory to systemd.
+pub enum Cgroup {
+    Fs { path: PathBuf },
+    Systemd { path: PathBuf, unit: String },
+}
+
+impl Cgroup {
+    /// Apply `resources` to `pid` under `cgroups_path`, creating the
+    /// hierarchy via the plain filesystem driver or, when `systemd_cgroup`
+    /// is set, via a systemd transient scope.
+    pub fn apply(
+        cgroups_path: &str,
+        resources: Option<&Resources>,
+        pid: i32,
+        systemd_cgroup: bool,
+    ) -> Result<Self> {
+        if systemd_cgroup {
+            Self::apply_systemd(cgroups_path, resources, pid)
+        } else {
+            Self::apply_fs(cgroups_path, resources, pid)
+        }
+    }
+
+    fn apply_fs(cgroups_path: &str, resources: Option<&Resources>, pid: i32) -> Result<Self> {
+        let path = fs_cgroup_path(cgroups_path);
+        create_dir_all(&path)
+            .with_context(|| format!("failed to create cgroup directory {:?}", path))?;
+
+        enable_controllers(path.parent().unwrap_or(Path::new(CGROUP_ROOT)).to_path_buf())?;
+
+        if let Some(resources) = resources {
+            apply_resources(&path, resources)?;
+        }
+
+        write(path.join("cgroup.procs"), pid.to_string())
+            .with_context(|| format!("failed to move pid {} into {:?}", pid, path))?;
+
+        Ok(Cgroup::Fs { path })
+    }
+
+    fn apply_systemd(cgroups_path: &str, resources: Option<&Resources>, pid: i32) -> Result<Self> {
+        let (slice, prefix, name) = split_systemd_cgroups_path(cgroups_path)?;
+        let unit = format!("{}-{}.scope", prefix, name);
+
+        // The D-Bus call is split out so the properties we pass can be unit
+        // tested independently of an actual systemd connection.
+        let properties = systemd_unit_properties(&slice, pid, resources);
+        start_transient_scope(&unit, &slice, pid, properties)
+            .with_context(|| format!("failed to start transient scope {}", unit))?;
+
+        let path = systemd_scope_path(&slice, &unit);
+        Ok(Cgroup::Systemd { path, unit })
+    }
+
+    /// Reconstruct the handle `apply` would have returned from just
+    /// `cgroups_path` and the driver a container was created with -- used
+    /// by `delete`, which only has those two persisted facts (from
+    /// `config.json` and `state.json` respectively) to work with, not the
+    /// original `Cgroup` from `create`.
+    pub fn from_saved(cgroups_path: &str, systemd_cgroup: bool) -> Result<Self> {
+        if systemd_cgroup {
+            let (slice, prefix, name) = split_systemd_cgroups_path(cgroups_path)?;
+            let unit = format!("{}-{}.scope", prefix, name);
+            let path = systemd_scope_path(&slice, &unit);
+            Ok(Cgroup::Systemd { path, unit })
+        } else {
+            Ok(Cgroup::Fs {
+                path: fs_cgroup_path(cgroups_path),
+            })
+        }
+    }
+
+    /// Remove the cgroup directory (fs driver) or stop the transient scope
+    /// and let systemd reap its cgroup (systemd driver).
+    pub fn delete(&self) -> Result<()> {
+        match self {
+            Cgroup::Fs { path } => {
+                if path.exists() {
+                    remove_dir(path)
+                        .with_context(|| format!("failed to remove cgroup {:?}", path))?;
+                }
+                Ok(())
+            }
+            Cgroup::Systemd { unit, .. } => stop_transient_scope(unit),
+        }
+    }
+}
+
+fn fs_cgroup_path(cgroups_path: &str) -> PathBuf {
+    PathBuf::from(CGROUP_ROOT).join(cgroups_path.trim_start_matches('/'))
+}
+
+/// Enable the controllers we need on every ancestor of `path` by writing
+/// `+memory +cpu +pids` to each `cgroup.subtree_control`, starting at the
+/// cgroup root and working down to `path`'s parent.
+fn enable_controllers(mut dir: PathBuf) -> Result<()> {
+    let root = PathBuf::from(CGROUP_ROOT);
+    let enable = CONTROLLERS
+        .iter()
+        .map(|c| format!("+{}", c))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut to_enable = vec![];
+    while dir.starts_with(&root) {
+        to_enable.push(dir.clone());
+        if dir == root {
+            break;
+        }
+        dir = dir.parent().unwrap_or(&root).to_path_buf();
+    }
+
+    for dir in to_enable.into_iter().rev() {
+        create_dir_all(&dir).ok();
+        let subtree_control = dir.join("cgroup.subtree_control");
+        // Controllers may already be enabled by a previous container; a
+        // write that only re-enables existing controllers is a no-op.
+        let _ = write(&subtree_control, &enable);
+    }
+    Ok(())
+}
+
+fn apply_resources(path: &Path, resources: &Resources) -> Result<()> {
+    if let Some(memory) = &resources.memory {
+        apply_memory(path, memory)?;
+    }
+    if let Some(cpu) = &resources.cpu {
+        apply_cpu(path, cpu)?;
+    }
+    if let Some(pids) = &resources.pids {
+        apply_pids(path, pids)?;
+    }
+    Ok(())
+}
+
+fn apply_memory(path: &Path, memory: &MemoryResources) -> Result<()> {
+    if let Some(limit) = memory.limit {
+        write(path.join("memory.max"), limit.to_string())
+            .context("failed to write memory.max")?;
+    }
+    Ok(())
+}
+
+fn apply_cpu(path: &Path, cpu: &CpuResources) -> Result<()> {
+    if let Some(quota) = cpu.quota {
+        let period = cpu.period.unwrap_or(100_000);
+        write(path.join("cpu.max"), format!("{} {}", quota, period))
+            .context("failed to write cpu.max")?;
+    }
+    Ok(())
+}
+
+fn apply_pids(path: &Path, pids: &PidsResources) -> Result<()> {
+    if let Some(limit) = pids.limit {
+        write(path.join("pids.max"), limit.to_string()).context("failed to write pids.max")?;
+    }
+    Ok(())
+}
+
+/// Split a `cgroupsPath` of the form `slice:prefix:name` as required by the
+/// systemd cgroup driver, e.g. `system.slice:hako:deadbeef`.
+fn split_systemd_cgroups_path(cgroups_path: &str) -> Result<(String, String, String)> {
+    let parts: Vec<&str> = cgroups_path.split(':').collect();
+    match parts.as_slice() {
+        [slice, prefix, name] => Ok((slice.to_string(), prefix.to_string(), name.to_string())),
+        _ => bail!(
+            "systemd cgroupsPath must be of the form slice:prefix:name, got {:?}",
+            cgroups_path
+        ),
+    }
+}
+
+fn systemd_scope_path(slice: &str, unit: &str) -> PathBuf {
+    PathBuf::from(CGROUP_ROOT)
+        .join(slice.replace('-', "/").replace(".slice", ".slice"))
+        .join(unit)
+}
+
+fn systemd_unit_properties(
+    _slice: &str,
+    _pid: i32,
+    resources: Option<&Resources>,
+) -> Vec<(&'static str, u64)> {
+    let mut properties = vec![];
+    if let Some(resources) = resources {
+        if let Some(memory) = &resources.memory {
+            if let Some(limit) = memory.limit {
+                properties.push(("MemoryMax", limit as u64));
+            }
+        }
+        if let Some(pids) = &resources.pids {
+            if let Some(limit) = pids.limit {
+                properties.push(("TasksMax", limit as u64));
+            }
+        }
+    }
+    properties
+}
+
+const SYSTEMD_BUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Create a transient scope unit named `unit` under `slice`, with `pid` as
+/// its only initial member, via `org.freedesktop.systemd1.Manager`'s
+/// `StartTransientUnit` over the system D-Bus.
+fn start_transient_scope(
+    unit: &str,
+    slice: &str,
+    pid: i32,
+    properties: Vec<(&'static str, u64)>,
+) -> Result<()> {
+    let conn = Connection::new_system().context("failed to connect to the system D-Bus")?;
+    let manager = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        SYSTEMD_BUS_TIMEOUT,
+    );
+
+    let mut props: Vec<(&str, Variant<Box<dyn RefArg>>)> = vec![
+        ("PIDs", Variant(Box::new(vec![pid as u32]))),
+        ("Slice", Variant(Box::new(slice.to_string()))),
+    ];
+    for (name, value) in properties {
+        props.push((name, Variant(Box::new(value))));
+    }
+    let aux: Vec<(&str, Vec<(&str, Variant<Box<dyn RefArg>>)>)> = vec![];
+
+    let (_job,): (dbus::Path,) = manager
+        .method_call(
+            "org.freedesktop.systemd1.Manager",
+            "StartTransientUnit",
+            (unit, "fail", props, aux),
+        )
+        .context("StartTransientUnit D-Bus call failed")?;
+    Ok(())
+}
+
+/// Stop `unit` via `org.freedesktop.systemd1.Manager`'s `StopUnit`, letting
+/// systemd tear down the scope's cgroup along with it.
+fn stop_transient_scope(unit: &str) -> Result<()> {
+    let conn = Connection::new_system().context("failed to connect to the system D-Bus")?;
+    let manager = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        SYSTEMD_BUS_TIMEOUT,
+    );
+
+    let (_job,): (dbus::Path,) = manager
+        .method_call("org.freedesktop.systemd1.Manager", "StopUnit", (unit, "fail"))
+        .context("StopUnit D-Bus call failed")?;
+    Ok(())
+}
@@ -0,0 +1,418 @@
+use std::ffi::CString;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use nix::libc::{ioctl, TIOCSCTTY};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::pty::{openpty, OpenptyResult};
+use nix::sys::signal::{self, kill, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::socket::{
+    accept, recv, recvmsg, send, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, RecvMsg,
+};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{chdir, close, dup2, execvp, fork, pipe, read, setsid, write, ForkResult, Pid};
+use serde::{Deserialize, Serialize};
+
+use crate::seccomp;
+use crate::state::{State, Status};
+use crate::{container_dir, CreateContext};
+
+const MAX_MSG: usize = 4096;
+
+/// Write end of the self-pipe `handle_sigchld` wakes `monitor_loop`'s
+/// `poll` through. There is only ever one monitor loop per process, so a
+/// single static is simpler than threading a channel through a signal
+/// handler.
+static SIGCHLD_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_sigchld(_: i32) {
+    let fd = SIGCHLD_PIPE_WRITE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let _ = unsafe { nix::libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// One request over `exec.sock`'s persistent control protocol.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Request {
+    /// Run the container's `process` from `config.json`, as `hako start` does.
+    Start,
+    /// Run an ad hoc command inside the container's namespaces, as
+    /// `hako exec` does. The caller must follow this message with its
+    /// stdin/stdout/stderr fds over `SCM_RIGHTS`; with `terminal` set, the
+    /// server allocates the pty itself and proxies it to those fds.
+    Exec {
+        argv: Vec<String>,
+        env: Vec<String>,
+        cwd: String,
+        terminal: bool,
+    },
+    Kill {
+        signal: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl Response {
+    fn ok(exit_code: i32) -> Self {
+        Self {
+            exit_code: Some(exit_code),
+            error: None,
+        }
+    }
+
+    fn err(msg: impl ToString) -> Self {
+        Self {
+            exit_code: None,
+            error: Some(msg.to_string()),
+        }
+    }
+}
+
+pub fn send_request(fd: RawFd, req: &Request) -> Result<()> {
+    send(fd, &serde_json::to_vec(req)?, MsgFlags::empty())?;
+    Ok(())
+}
+
+pub fn recv_request(fd: RawFd) -> Result<Request> {
+    let mut buf = vec![0u8; MAX_MSG];
+    let len = recv(fd, &mut buf, MsgFlags::empty())?;
+    buf.truncate(len);
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+pub fn send_response(fd: RawFd, resp: &Response) -> Result<()> {
+    send(fd, &serde_json::to_vec(resp)?, MsgFlags::empty())?;
+    Ok(())
+}
+
+pub fn recv_response(fd: RawFd) -> Result<Response> {
+    let mut buf = vec![0u8; MAX_MSG];
+    let len = recv(fd, &mut buf, MsgFlags::empty())?;
+    buf.truncate(len);
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Hand `fds` to whoever is on the other end of `conn` over `SCM_RIGHTS`.
+pub fn send_fds(conn: RawFd, fds: &[RawFd]) -> Result<()> {
+    let iov = [IoSlice::new(b"fds")];
+    let cmsg = ControlMessage::ScmRights(fds);
+    sendmsg::<()>(conn, &iov, &[cmsg], MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+fn recv_fds(conn: RawFd, max: usize) -> Result<Vec<RawFd>> {
+    let mut buf = [0u8; 16];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; 3]);
+    let msg: RecvMsg<()> = recvmsg(conn, &mut iov, Some(&mut cmsg_buf), MsgFlags::empty())?;
+
+    let mut fds = vec![];
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            fds.extend(received.into_iter().take(max));
+        }
+    }
+    Ok(fds)
+}
+
+/// The monitor loop: becomes the container's real PID 1 (inside every
+/// namespace the container was given) and serves `Start`/`Exec`/`Kill`
+/// requests over `exec.sock` for as long as the container lives. The
+/// user's payload and every `exec`'d command run as children of this
+/// loop, so neither ever needs to `setns` -- they are already there.
+pub fn monitor_loop(listener: RawFd, ctx: &CreateContext) -> Result<()> {
+    let mut payload_pid: Option<Pid> = None;
+
+    // `accept` alone can't tell us when the payload exits on its own (no
+    // client ever connects to report it), so we also wake on SIGCHLD via a
+    // self-pipe and reap in a non-blocking loop.
+    let (sigchld_read, sigchld_write) = pipe().context("failed to create the sigchld pipe")?;
+    SIGCHLD_PIPE_WRITE.store(sigchld_write.as_raw_fd(), Ordering::Relaxed);
+    unsafe {
+        signal::sigaction(
+            Signal::SIGCHLD,
+            &SigAction::new(
+                SigHandler::Handler(handle_sigchld),
+                SaFlags::SA_RESTART,
+                SigSet::empty(),
+            ),
+        )?;
+    }
+
+    loop {
+        let mut poll_fds = [
+            PollFd::new(
+                unsafe { BorrowedFd::borrow_raw(listener) },
+                PollFlags::POLLIN,
+            ),
+            PollFd::new(
+                unsafe { BorrowedFd::borrow_raw(sigchld_read.as_raw_fd()) },
+                PollFlags::POLLIN,
+            ),
+        ];
+        poll(&mut poll_fds, PollTimeout::NONE)?;
+
+        if poll_fds[1]
+            .revents()
+            .is_some_and(|e| e.contains(PollFlags::POLLIN))
+        {
+            let mut buf = [0u8; 64];
+            let _ = read(sigchld_read.as_raw_fd(), &mut buf);
+
+            loop {
+                match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::Exited(pid, _)) | Ok(WaitStatus::Signaled(pid, _, _)) => {
+                        if Some(pid) == payload_pid {
+                            mark_stopped(ctx);
+                            return Ok(());
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        if !poll_fds[0]
+            .revents()
+            .is_some_and(|e| e.contains(PollFlags::POLLIN))
+        {
+            continue;
+        }
+
+        let conn = accept(listener)?;
+
+        let req = match recv_request(conn) {
+            Ok(req) => req,
+            Err(_) => {
+                let _ = close(conn);
+                continue;
+            }
+        };
+
+        match req {
+            Request::Start => {
+                payload_pid = Some(spawn_payload(ctx)?);
+                let _ = close(conn);
+            }
+            Request::Exec {
+                argv,
+                env,
+                cwd,
+                terminal,
+            } => {
+                // Run off the accept loop: an exec'd command (especially an
+                // interactive `-t` one) can run indefinitely, and blocking
+                // here would wedge `Kill` and every other `exec` behind it.
+                thread::spawn(move || run_exec(conn, &argv, &env, &cwd, terminal));
+            }
+            Request::Kill { signal } => {
+                // Before `Start`, there is no payload to target, but the
+                // monitor loop *is* the container's pid 1 (it's what
+                // `state.pid()` names), so signalling it is what tears a
+                // `created`-but-not-yet-started container down -- matching
+                // OCI semantics for `kill` on either lifecycle state.
+                let target = payload_pid.unwrap_or_else(Pid::this);
+                let resp = match Signal::try_from(signal)
+                    .context("invalid signal")
+                    .and_then(|signal| kill(target, signal).context("failed to signal container"))
+                {
+                    Ok(()) => Response::ok(0),
+                    Err(err) => Response::err(err),
+                };
+                let _ = send_response(conn, &resp);
+                let _ = close(conn);
+            }
+        }
+    }
+}
+
+/// The monitor is the only process that can know the instant its own
+/// payload exits, so it is responsible for flipping the persisted state to
+/// `Stopped` -- otherwise `hako state`/`delete` would keep reporting the
+/// container as running until some unrelated client happened to connect.
+fn mark_stopped(ctx: &CreateContext) {
+    let dir = container_dir(&ctx.root, &ctx.container_id);
+    if let Ok(mut state) = State::load(&dir) {
+        state.set_status(Status::Stopped);
+        let _ = state.save(&dir);
+    }
+}
+
+/// Fork the container's main process and `execvp` `spec.process`, loading
+/// the seccomp filter (if any) immediately before the `execvp` so it
+/// constrains the payload but none of our own setup syscalls.
+fn spawn_payload(ctx: &CreateContext) -> Result<Pid> {
+    match unsafe { fork().context("failed to fork the container payload")? } {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => {
+            let _ = chdir(ctx.spec.process.cwd.as_path());
+
+            let args: Vec<CString> = ctx
+                .spec
+                .process
+                .args
+                .iter()
+                .map(|s| CString::new(s.as_str()).unwrap())
+                .collect();
+
+            if let Some(linux) = &ctx.spec.linux {
+                if let Some(seccomp) = &linux.seccomp {
+                    if seccomp::load(seccomp).is_err() {
+                        std::process::exit(126);
+                    }
+                }
+            }
+
+            let _ = execvp(&args[0], &args);
+            std::process::exit(127);
+        }
+    }
+}
+
+/// Run one `exec` request to completion on its own thread: fork, dup the
+/// fds the client passed over `SCM_RIGHTS` onto 0/1/2, `execvp` the
+/// requested command, wait for it, and report the result back over `conn`.
+/// Owns `conn` for as long as the exec'd process runs, since it no longer
+/// shares the monitor's accept loop.
+fn run_exec(conn: RawFd, argv: &[String], env: &[String], cwd: &str, terminal: bool) {
+    let resp = match run_exec_inner(conn, argv, env, cwd, terminal) {
+        Ok(code) => Response::ok(code),
+        Err(err) => Response::err(err),
+    };
+    let _ = send_response(conn, &resp);
+    let _ = close(conn);
+}
+
+fn run_exec_inner(
+    conn: RawFd,
+    argv: &[String],
+    env: &[String],
+    cwd: &str,
+    terminal: bool,
+) -> Result<i32> {
+    if argv.is_empty() {
+        bail!("exec requires a command");
+    }
+
+    let fds = recv_fds(conn, 3).context("failed to receive stdio fds")?;
+    let pty = if terminal {
+        Some(openpty(None, None).context("failed to allocate a pty for the exec'd process")?)
+    } else {
+        None
+    };
+
+    match unsafe { fork().context("failed to fork the exec'd process")? } {
+        ForkResult::Parent { child } => {
+            if let Some(OpenptyResult { master, slave }) = pty {
+                drop(slave);
+                proxy_pty(master.as_raw_fd(), fds[0], fds[1]);
+            }
+            for fd in &fds {
+                let _ = close(*fd);
+            }
+            match waitpid(child, None)? {
+                WaitStatus::Exited(_, code) => Ok(code),
+                WaitStatus::Signaled(_, sig, _) => Ok(128 + sig as i32),
+                _ => Ok(-1),
+            }
+        }
+        ForkResult::Child => {
+            let _ = setsid();
+            if let Some(OpenptyResult { master, slave }) = &pty {
+                drop(master);
+                for i in 0..3 {
+                    let _ = dup2(slave.as_raw_fd(), i as RawFd);
+                }
+                let _ = unsafe { ioctl(slave.as_raw_fd(), TIOCSCTTY) };
+            } else {
+                for (i, fd) in fds.iter().enumerate() {
+                    let _ = dup2(*fd, i as RawFd);
+                }
+            }
+            apply_env(env);
+            let _ = chdir(cwd);
+
+            let args: Vec<CString> = argv
+                .iter()
+                .map(|s| CString::new(s.as_str()).unwrap())
+                .collect();
+            let _ = execvp(&args[0], &args);
+            std::process::exit(127);
+        }
+    }
+}
+
+/// Replace the current process's environment with `env` (`KEY=VALUE`
+/// pairs), the way `execvpe` would -- called in the forked exec child
+/// right before `execvp`, so it never touches the long-lived monitor's
+/// own environment.
+fn apply_env(env: &[String]) {
+    for (key, _) in std::env::vars_os() {
+        std::env::remove_var(key);
+    }
+    for kv in env {
+        if let Some((key, value)) = kv.split_once('=') {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
+/// Copy bytes between the pty `master` and the client's stdin/stdout fds
+/// until the exec'd command's end of the pty closes (EOF on `master`),
+/// giving it a real controlling terminal while the `hako exec` client on
+/// the other end still drives the actual I/O.
+fn proxy_pty(master: RawFd, client_in: RawFd, client_out: RawFd) {
+    let mut buf = [0u8; 4096];
+    let mut client_in_open = true;
+    loop {
+        let mut fds = vec![PollFd::new(
+            unsafe { BorrowedFd::borrow_raw(master) },
+            PollFlags::POLLIN,
+        )];
+        if client_in_open {
+            fds.push(PollFd::new(
+                unsafe { BorrowedFd::borrow_raw(client_in) },
+                PollFlags::POLLIN,
+            ));
+        }
+        if poll(&mut fds, PollTimeout::NONE).is_err() {
+            break;
+        }
+
+        if fds[0]
+            .revents()
+            .is_some_and(|e| e.contains(PollFlags::POLLIN))
+        {
+            match read(master, &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let _ = write(unsafe { BorrowedFd::borrow_raw(client_out) }, &buf[..n]);
+                }
+            }
+        }
+
+        if client_in_open
+            && fds[1]
+                .revents()
+                .is_some_and(|e| e.contains(PollFlags::POLLIN))
+        {
+            match read(client_in, &mut buf) {
+                Ok(0) | Err(_) => client_in_open = false,
+                Ok(n) => {
+                    let _ = write(unsafe { BorrowedFd::borrow_raw(master) }, &buf[..n]);
+                }
+            }
+        }
+    }
+}
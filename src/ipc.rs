@@ -0,0 +1,101 @@
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+use anyhow::{bail, Result};
+use nix::sys::socket::{
+    recv, recvmsg, send, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, RecvMsg,
+};
+use serde::{Deserialize, Serialize};
+
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// A step in the parent/intermediate/init handshake that happens while a
+/// container is created, replacing the raw strings the original protocol
+/// parsed PIDs and readiness out of.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SyncMessage {
+    /// Sent by the intermediate process once it has forked init: carries
+    /// init's pid in the runtime's own namespace.
+    IntermediateReady { pid: i32 },
+    /// Sent by whichever side just finished writing uid_map/gid_map, to
+    /// release the other side to continue.
+    MappingWritten,
+    /// Marks the container's cgroup as created with the init pid moved
+    /// into it. No handshake step sends it: the intermediate process
+    /// applies the cgroup after `IntermediateReady` and the parent does
+    /// not wait for that step.
+    CgroupApplied,
+    /// Sent by the init process twice: on `init_channel` once it has
+    /// unshared its user namespace (only when it has one), and on
+    /// `grandchild_channel` once every namespace, mount, and socket it
+    /// owns is set up, right before it enters its control loop.
+    InitReady,
+    /// Sent instead of any of the above when a setup step failed, so the
+    /// receiving side doesn't block forever waiting on a process that
+    /// already exited.
+    Error { msg: String },
+}
+
+/// A `SOCK_SEQPACKET` connection carrying length-prefixed `SyncMessage`s
+/// and, when needed, file descriptors passed over `SCM_RIGHTS` -- the
+/// console pty master, a seccomp notify fd, and so on.
+pub struct IpcChannel {
+    fd: OwnedFd,
+}
+
+impl IpcChannel {
+    pub fn new(fd: OwnedFd) -> Self {
+        Self { fd }
+    }
+
+    pub fn send(&self, msg: &SyncMessage) -> Result<()> {
+        let payload = serde_json::to_vec(msg)?;
+        let mut framed = Vec::with_capacity(4 + payload.len());
+        framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        framed.extend_from_slice(&payload);
+        send(self.fd.as_raw_fd(), &framed, MsgFlags::empty())?;
+        Ok(())
+    }
+
+    pub fn recv(&self) -> Result<SyncMessage> {
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        let n = recv(self.fd.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+        if n < 4 {
+            bail!("short read on ipc channel");
+        }
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        if 4 + len > n {
+            bail!("truncated ipc message");
+        }
+        match serde_json::from_slice(&buf[4..4 + len])? {
+            SyncMessage::Error { msg } => bail!(msg),
+            msg => Ok(msg),
+        }
+    }
+
+    /// Hand `fd` to the other end of this channel over `SCM_RIGHTS`.
+    pub fn send_fd(&self, fd: RawFd) -> Result<()> {
+        let iov = [IoSlice::new(b"fd")];
+        let cmsg = ControlMessage::ScmRights(&[fd]);
+        sendmsg::<()>(self.fd.as_raw_fd(), &iov, &[cmsg], MsgFlags::empty(), None)?;
+        Ok(())
+    }
+
+    /// Receive one fd sent with `send_fd`.
+    pub fn recv_fd(&self) -> Result<RawFd> {
+        let mut buf = [0u8; 16];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; 1]);
+        let msg: RecvMsg<()> =
+            recvmsg(self.fd.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), MsgFlags::empty())?;
+
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(fd) = fds.into_iter().next() {
+                    return Ok(fd);
+                }
+            }
+        }
+        bail!("no fd received on ipc channel")
+    }
+}
@@ -1,12 +1,14 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::env::{self, set_current_dir};
-use std::ffi::{CStr, CString};
+use std::env;
+use std::ffi::CStr;
 use std::fs::{create_dir_all, read_to_string, File, OpenOptions};
-use std::io::{IoSlice, Write};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::str::FromStr;
 
 use clap::{Parser, Subcommand, ValueEnum};
 use nix::libc::{ioctl, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, TIOCSCTTY};
@@ -16,16 +18,85 @@ use serde::{Deserialize, Serialize};
 
 use nix::pty::{openpty, OpenptyResult};
 use nix::sys::socket::{
-    accept, bind, connect, listen, recv, send, sendmsg, socket, socketpair, AddressFamily, Backlog,
-    ControlMessage, MsgFlags, SockFlag, SockType, UnixAddr,
+    bind, connect, listen, socket, socketpair, AddressFamily, Backlog, SockFlag, SockType,
+    UnixAddr,
 };
 
-use nix::unistd::{close, dup2, execvp, fork, pivot_root, setsid, ForkResult};
+use nix::sys::signal::Signal;
+use nix::unistd::{
+    close, dup2, fork, pivot_root, setgid, setgroups, setsid, setuid, ForkResult, Gid, Uid,
+};
+
+use anyhow::{bail, Context, Error, Result};
 
-use anyhow::{Context, Error, Result};
+mod cgroups;
+mod exec;
+mod ipc;
+mod mounts;
+mod seccomp;
+mod state;
+mod userns;
+
+use cgroups::Cgroup;
+use ipc::{IpcChannel, SyncMessage};
+use seccomp::Seccomp;
+use state::{State, Status};
+use userns::IdMapping;
 
 const HAKO_ROOT: &str = "/run/hako";
-const EXEC_SOCK: &str = "exec.sock";
+
+/// The directory `<root>/<container_id>` where a container's state and
+/// other runtime metadata live.
+///
+/// Uses the full `container_id`: two containers whose ids merely share a
+/// prefix must not be able to collide on the same directory and silently
+/// corrupt each other's `state.json`/cgroup path.
+pub(crate) fn container_dir(root: &Path, container_id: &str) -> PathBuf {
+    root.join(container_id)
+}
+
+/// Best-effort: signal a still-alive container to death via `exec.sock`'s
+/// `Kill` protocol and wait for its pid to actually disappear, so
+/// `--force` delete doesn't hand a populated cgroup to `Cgroup::delete`
+/// and fail with `EBUSY` -- the entire point of forcing the delete.
+fn force_kill(root: &Path, container_id: &str, pid: i32) {
+    if let Ok(sock) = socket(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        if let Ok(addr) = UnixAddr::new(&exec_sock_path(root, container_id)) {
+            if connect(sock.as_raw_fd(), &addr).is_ok() {
+                let _ = exec::send_request(
+                    sock.as_raw_fd(),
+                    &exec::Request::Kill {
+                        signal: Signal::SIGKILL as i32,
+                    },
+                );
+                let _ = exec::recv_response(sock.as_raw_fd());
+            }
+        }
+    }
+
+    for _ in 0..50 {
+        if !Path::new(&format!("/proc/{}", pid)).exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+/// The path of a container's `exec.sock`, kept separate from
+/// `container_dir`: `sockaddr_un` caps the whole path at 108 bytes, which a
+/// long `container_id` can blow through, so the socket lives directly
+/// under `root` under a short hash of the id instead of the (unshortened)
+/// per-container directory.
+fn exec_sock_path(root: &Path, container_id: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    container_id.hash(&mut hasher);
+    root.join(format!("{:016x}.sock", hasher.finish()))
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -37,6 +108,8 @@ struct Spec {
     domainname: Option<String>,
     mounts: Option<Vec<Mount>>,
     linux: Option<Linux>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
 }
 
 impl TryFrom<&Path> for Spec {
@@ -84,6 +157,7 @@ struct User {
 struct Mount {
     destination: String,
     source: Option<String>,
+    r#type: Option<String>,
     options: Option<Vec<String>>,
 }
 
@@ -91,6 +165,38 @@ struct Mount {
 #[serde(rename_all = "camelCase")]
 struct Linux {
     namespaces: Vec<Namespace>,
+    resources: Option<Resources>,
+    cgroups_path: Option<String>,
+    uid_mappings: Option<Vec<IdMapping>>,
+    gid_mappings: Option<Vec<IdMapping>>,
+    seccomp: Option<Seccomp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Resources {
+    memory: Option<MemoryResources>,
+    cpu: Option<CpuResources>,
+    pids: Option<PidsResources>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MemoryResources {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CpuResources {
+    quota: Option<i64>,
+    period: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PidsResources {
+    limit: Option<i64>,
 }
 
 impl Linux {
@@ -117,28 +223,6 @@ struct Namespace {
     r#type: String,
 }
 
-struct IpcChannel {
-    fd: OwnedFd,
-}
-
-impl IpcChannel {
-    fn new(fd: OwnedFd) -> Self {
-        Self { fd }
-    }
-
-    fn send(&self, msg: &str) -> Result<()> {
-        send(self.fd.as_raw_fd(), msg.as_bytes(), MsgFlags::empty())?;
-        Ok(())
-    }
-
-    fn recv(&self) -> Result<String> {
-        let mut buf = vec![0; 1024];
-        let len = recv(self.fd.as_raw_fd(), &mut buf, MsgFlags::empty())?;
-        buf.truncate(len);
-        Ok(String::from_utf8(buf)?)
-    }
-}
-
 #[derive(Parser)]
 #[command(version = "0.0.1", about = "Open Container Initiative runtime", long_about = None)]
 struct Cli {
@@ -186,6 +270,14 @@ enum Commands {
         #[arg(long = "force")]
         force: bool,
     },
+    /// execute a new process inside a running container
+    Exec {
+        container_id: String,
+        #[arg(long = "tty", short = 't')]
+        terminal: bool,
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
 }
 
 #[derive(Clone)]
@@ -197,6 +289,7 @@ struct CreateContext {
     pid_file: Option<PathBuf>,
     root: PathBuf,
     log: PathBuf,
+    systemd_cgroup: bool,
 }
 
 fn create(ctx: CreateContext) -> Result<()> {
@@ -223,11 +316,30 @@ fn create(ctx: CreateContext) -> Result<()> {
             let grandchild_channel = IpcChannel::new(parent_grandchild_sock);
 
             // wait until the intermediate process is ready
-            let init_pid = child_channel.recv()?;
-            println!("from child channel: {}", init_pid);
+            let init_pid = match child_channel.recv()? {
+                SyncMessage::IntermediateReady { pid } => pid,
+                msg => bail!(
+                    "unexpected message while waiting for the intermediate process: {:?}",
+                    msg
+                ),
+            };
+
+            let dir = container_dir(&ctx.root, &ctx.container_id);
+            create_dir_all(&dir)?;
+            let mut state = State::new(
+                ctx.container_id.clone(),
+                ctx.path_to_bundle.clone(),
+                init_pid,
+                ctx.spec.annotations.clone(),
+                ctx.systemd_cgroup,
+            );
+            state.save(&dir)?;
 
             // wait until the init process is ready
-            println!("from grandchild channel: {}", grandchild_channel.recv()?);
+            grandchild_channel.recv()?;
+
+            state.set_status(Status::Created);
+            state.save(&dir)?;
 
             // update pid file
             if let Some(pid_file) = ctx.pid_file {
@@ -244,7 +356,10 @@ fn create(ctx: CreateContext) -> Result<()> {
             let child_channel = IpcChannel::new(child_parent_sock);
             let grandchild_channel = IpcChannel::new(grandchild_parent_sock);
 
-            if let Err(_) = intermediate_process(ctx.clone(), child_channel, grandchild_channel) {
+            if let Err(err) =
+                intermediate_process(ctx.clone(), child_channel, grandchild_channel)
+            {
+                eprintln!("hako: {:#}", err);
                 exit(1);
             }
 
@@ -258,24 +373,80 @@ fn intermediate_process(
     child_channel: IpcChannel,
     grandchild_channel: IpcChannel,
 ) -> Result<()> {
-    // TODO: set up cgroup
+    // the init process reports its own failures over `grandchild_channel`
+    // and `init_channel` directly, so only our own setup steps need to be
+    // wrapped here
+    match intermediate_process_inner(&ctx, &child_channel, grandchild_channel) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = child_channel.send(&SyncMessage::Error {
+                msg: err.to_string(),
+            });
+            Err(err)
+        }
+    }
+}
 
+fn intermediate_process_inner(
+    ctx: &CreateContext,
+    child_channel: &IpcChannel,
+    grandchild_channel: IpcChannel,
+) -> Result<()> {
     if let Some(linux) = &ctx.spec.linux {
         unshare(linux.clone_flags() & CloneFlags::CLONE_NEWPID)?;
     }
 
+    let (parent_init_sock, init_parent_sock) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+
     match unsafe { fork().context("failed to create a init process")? } {
         ForkResult::Parent { child: child_pid } => {
             drop(grandchild_channel);
+            drop(init_parent_sock);
+
+            let init_channel = IpcChannel::new(parent_init_sock);
+
+            child_channel.send(&SyncMessage::IntermediateReady {
+                pid: child_pid.as_raw(),
+            })?;
+
+            if let Some(linux) = &ctx.spec.linux {
+                if linux.clone_flags().contains(CloneFlags::CLONE_NEWUSER) {
+                    userns::write_id_mappings(
+                        child_pid.as_raw(),
+                        linux.uid_mappings.as_deref().unwrap_or_default(),
+                        linux.gid_mappings.as_deref().unwrap_or_default(),
+                        &init_channel,
+                    )
+                    .context("failed to write user namespace id mappings")?;
+                }
+            }
 
-            child_channel.send(child_pid.to_string().as_str())?;
+            if let Some(linux) = &ctx.spec.linux {
+                if let Some(cgroups_path) = &linux.cgroups_path {
+                    Cgroup::apply(
+                        cgroups_path,
+                        linux.resources.as_ref(),
+                        child_pid.as_raw(),
+                        ctx.systemd_cgroup,
+                    )
+                    .context("failed to set up cgroup")?;
+                }
+            }
 
             Ok(())
         }
         ForkResult::Child => {
-            drop(child_channel);
+            drop(parent_init_sock);
 
-            if let Err(err) = init_process(ctx.clone(), grandchild_channel) {
+            let init_channel = IpcChannel::new(init_parent_sock);
+
+            if let Err(err) = init_process(ctx.clone(), grandchild_channel, init_channel) {
+                eprintln!("hako: failed to initialize container: {:#}", err);
                 exit(1);
             }
 
@@ -284,7 +455,49 @@ fn intermediate_process(
     }
 }
 
-fn init_process(ctx: CreateContext, grandchild_channel: IpcChannel) -> Result<()> {
+fn init_process(
+    ctx: CreateContext,
+    grandchild_channel: IpcChannel,
+    init_channel: IpcChannel,
+) -> Result<()> {
+    match init_process_inner(ctx, &grandchild_channel, &init_channel) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let msg = err.to_string();
+            let _ = grandchild_channel.send(&SyncMessage::Error { msg: msg.clone() });
+            let _ = init_channel.send(&SyncMessage::Error { msg });
+            Err(err)
+        }
+    }
+}
+
+fn init_process_inner(
+    ctx: CreateContext,
+    grandchild_channel: &IpcChannel,
+    init_channel: &IpcChannel,
+) -> Result<()> {
+    if let Some(linux) = &ctx.spec.linux {
+        if linux.clone_flags().contains(CloneFlags::CLONE_NEWUSER) {
+            unshare(CloneFlags::CLONE_NEWUSER)?;
+
+            // tell the parent our user namespace exists, then block until
+            // it has written uid_map/gid_map for us
+            init_channel.send(&SyncMessage::InitReady)?;
+            init_channel.recv()?;
+
+            let user = &ctx.spec.process.user;
+            if let Some(additional_gids) = &user.additional_gids {
+                let groups: Vec<Gid> = additional_gids
+                    .iter()
+                    .map(|gid| Gid::from_raw(*gid as u32))
+                    .collect();
+                setgroups(&groups)?;
+            }
+            setgid(Gid::from_raw(user.gid as u32))?;
+            setuid(Uid::from_raw(user.uid as u32))?;
+        }
+    }
+
     setsid()?;
 
     if ctx.spec.process.terminal {
@@ -298,18 +511,9 @@ fn init_process(ctx: CreateContext, grandchild_channel: IpcChannel) -> Result<()
             SockFlag::empty(),
             None,
         )?;
-        let console_sock_addr = UnixAddr::new(ctx.console_socket.unwrap().as_path())?;
+        let console_sock_addr = UnixAddr::new(ctx.console_socket.as_ref().unwrap().as_path())?;
         connect(console_socket.as_raw_fd(), &console_sock_addr)?;
-        let iov = [IoSlice::new(b"/dev/ptmx")];
-        let fds = [master.as_raw_fd()];
-        let cmsg = ControlMessage::ScmRights(&fds);
-        sendmsg::<()>(
-            console_socket.as_raw_fd(),
-            &iov,
-            &[cmsg],
-            MsgFlags::empty(),
-            None,
-        )?;
+        IpcChannel::new(console_socket).send_fd(master.as_raw_fd())?;
 
         if unsafe { ioctl(slave.as_raw_fd(), TIOCSCTTY) } < 0 {
             return Err(Error::msg("ioctl error"));
@@ -321,7 +525,7 @@ fn init_process(ctx: CreateContext, grandchild_channel: IpcChannel) -> Result<()
     }
 
     if let Some(linux) = &ctx.spec.linux {
-        unshare(linux.clone_flags() & !CloneFlags::CLONE_NEWPID)?;
+        unshare(linux.clone_flags() & !CloneFlags::CLONE_NEWPID & !CloneFlags::CLONE_NEWUSER)?;
     }
 
     mount(
@@ -340,16 +544,10 @@ fn init_process(ctx: CreateContext, grandchild_channel: IpcChannel) -> Result<()
         None::<&str>,
     )?;
 
-    let container_root = PathBuf::from_str(HAKO_ROOT)?.join(
-        ctx.container_id
-            .chars()
-            .take(10)
-            .collect::<String>()
-            .as_str(),
-    );
-    let socket_path = container_root.join(EXEC_SOCK);
+    let container_root = container_dir(&ctx.root, &ctx.container_id);
+    let socket_path = exec_sock_path(&ctx.root, &ctx.container_id);
 
-    create_dir_all(container_root)?;
+    create_dir_all(&container_root)?;
 
     let socket = socket(
         AddressFamily::Unix,
@@ -371,29 +569,30 @@ fn init_process(ctx: CreateContext, grandchild_channel: IpcChannel) -> Result<()
         None::<&str>,
     )?;
 
-    grandchild_channel.send("ready")?;
+    if let Some(spec_mounts) = &ctx.spec.mounts {
+        mounts::apply(Path::new("/"), spec_mounts)?;
+    }
 
-    // wait start
-    accept(socket.as_raw_fd())?;
-    set_current_dir(ctx.spec.process.cwd.as_path())?;
-    let args: Vec<CString> = ctx
-        .spec
-        .process
-        .args
-        .iter()
-        .map(|s| CString::new(s.as_str()).unwrap())
-        .collect();
+    if ctx.spec.root.readonly {
+        mounts::apply_root_readonly()?;
+    }
 
-    execvp(&args[0], &args)?;
+    grandchild_channel.send(&SyncMessage::InitReady)?;
 
-    Ok(())
+    // loops forever, spawning the container payload on `Start` and any
+    // number of `exec`'d commands alongside it
+    exec::monitor_loop(socket.as_raw_fd(), &ctx)
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
         Commands::State { container_id } => {
-            println!("state command {}", container_id);
+            let dir = container_dir(&cli.root, &container_id);
+            let mut state = State::load(&dir)
+                .with_context(|| format!("container {} does not exist", container_id))?;
+            state.refresh(&dir)?;
+            println!("{}", state.to_json()?);
         }
         Commands::Create {
             container_id,
@@ -413,14 +612,22 @@ fn main() -> Result<()> {
                 pid_file,
                 root: cli.root,
                 log: cli.log,
+                systemd_cgroup: cli.systemd_cgroup,
             })
             .context("failed to create a container")?;
         }
         Commands::Start { container_id } => {
-            println!("start command {}", container_id);
-            let container_root = PathBuf::from_str(HAKO_ROOT)?
-                .join(container_id.chars().take(10).collect::<String>().as_str());
-            let socket_path = container_root.join(EXEC_SOCK);
+            let dir = container_dir(&cli.root, &container_id);
+            let mut state = State::load(&dir)
+                .with_context(|| format!("container {} does not exist", container_id))?;
+            if state.status() != Status::Created {
+                return Err(Error::msg(format!(
+                    "cannot start a container in the {:?} state",
+                    state.status()
+                )));
+            }
+
+            let socket_path = exec_sock_path(&cli.root, &container_id);
             let socket = socket(
                 AddressFamily::Unix,
                 SockType::SeqPacket,
@@ -429,18 +636,117 @@ fn main() -> Result<()> {
             )?;
             let sock_addr = UnixAddr::new(&socket_path)?;
             connect(socket.as_raw_fd(), &sock_addr)?;
+            exec::send_request(socket.as_raw_fd(), &exec::Request::Start)?;
+
+            state.set_status(Status::Running);
+            state.save(&dir)?;
         }
         Commands::Kill {
             container_id,
             signal,
         } => {
-            println!("kill command {} {:?}", container_id, signal);
+            let dir = container_dir(&cli.root, &container_id);
+            let state = State::load(&dir)
+                .with_context(|| format!("container {} does not exist", container_id))?;
+            if !matches!(state.status(), Status::Created | Status::Running) {
+                return Err(Error::msg(format!(
+                    "cannot kill a container in the {:?} state",
+                    state.status()
+                )));
+            }
+
+            let signal = signal.unwrap_or(Signal::SIGTERM as i32);
+
+            let socket_path = exec_sock_path(&cli.root, &container_id);
+            let sock = socket(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                SockFlag::SOCK_CLOEXEC,
+                None,
+            )?;
+            connect(sock.as_raw_fd(), &UnixAddr::new(&socket_path)?)?;
+            exec::send_request(sock.as_raw_fd(), &exec::Request::Kill { signal })?;
+
+            let resp = exec::recv_response(sock.as_raw_fd())?;
+            if let Some(err) = resp.error {
+                return Err(Error::msg(err));
+            }
         }
         Commands::Delete {
             container_id,
             force,
         } => {
-            println!("delete command {}", container_id);
+            let dir = container_dir(&cli.root, &container_id);
+            let mut state = State::load(&dir)
+                .with_context(|| format!("container {} does not exist", container_id))?;
+            state.refresh(&dir)?;
+
+            if state.status() != Status::Stopped && !force {
+                return Err(Error::msg(format!(
+                    "cannot delete a container in the {:?} state without --force",
+                    state.status()
+                )));
+            }
+
+            if force && matches!(state.status(), Status::Created | Status::Running) {
+                force_kill(&cli.root, &container_id, state.pid());
+            }
+
+            let _ = std::fs::remove_file(exec_sock_path(&cli.root, &container_id));
+
+            if let Ok(spec) = Spec::try_from(state.bundle().join("config.json").as_path()) {
+                if let Some(cgroups_path) = spec.linux.as_ref().and_then(|l| l.cgroups_path.as_ref())
+                {
+                    Cgroup::from_saved(cgroups_path, state.systemd_cgroup())?.delete()?;
+                }
+            }
+
+            state::delete(&dir)?;
+        }
+        Commands::Exec {
+            container_id,
+            terminal,
+            command,
+        } => {
+            let dir = container_dir(&cli.root, &container_id);
+            let state = State::load(&dir)
+                .with_context(|| format!("container {} does not exist", container_id))?;
+            if !matches!(state.status(), Status::Created | Status::Running) {
+                return Err(Error::msg(format!(
+                    "cannot exec into a container in the {:?} state",
+                    state.status()
+                )));
+            }
+
+            let socket_path = exec_sock_path(&cli.root, &container_id);
+            let sock = socket(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                SockFlag::SOCK_CLOEXEC,
+                None,
+            )?;
+            connect(sock.as_raw_fd(), &UnixAddr::new(&socket_path)?)?;
+
+            let cwd = env::current_dir()?;
+            exec::send_request(
+                sock.as_raw_fd(),
+                &exec::Request::Exec {
+                    argv: command,
+                    env: env::vars().map(|(k, v)| format!("{}={}", k, v)).collect(),
+                    cwd: cwd.to_string_lossy().into_owned(),
+                    terminal,
+                },
+            )?;
+            exec::send_fds(
+                sock.as_raw_fd(),
+                &[STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO],
+            )?;
+
+            let resp = exec::recv_response(sock.as_raw_fd())?;
+            if let Some(err) = resp.error {
+                return Err(Error::msg(err));
+            }
+            exit(resp.exit_code.unwrap_or(1));
         }
     };
     Ok(())
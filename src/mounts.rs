@@ -0,0 +1,131 @@
+use std::fs::{create_dir_all, metadata, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use nix::mount::{mount, MsFlags};
+
+use crate::Mount;
+
+/// Apply every entry of `spec.mounts` against `new_root`. Must run after
+/// `pivot_root`, since `destination` is resolved against the container's
+/// new root, not the runtime's.
+pub fn apply(new_root: &Path, mounts: &[Mount]) -> Result<()> {
+    for m in mounts {
+        apply_one(new_root, m).with_context(|| format!("failed to mount {}", m.destination))?;
+    }
+    Ok(())
+}
+
+/// Remount `/` read-only, honoring `root.readonly`. Must run last, after
+/// every other mount in `spec.mounts` has already landed under it.
+pub fn apply_root_readonly() -> Result<()> {
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+fn apply_one(new_root: &Path, m: &Mount) -> Result<()> {
+    let dest = resolve_dest(new_root, &m.destination);
+    prepare_target(&dest, m)?;
+
+    let (mut flags, data) = parse_options(m.options.as_deref().unwrap_or(&[]));
+    let bind_readonly = flags.contains(MsFlags::MS_BIND) && flags.contains(MsFlags::MS_RDONLY);
+    if bind_readonly {
+        // the kernel rejects MS_BIND|MS_RDONLY in a single call; apply the
+        // bind first, then remount it read-only
+        flags.remove(MsFlags::MS_RDONLY);
+    }
+
+    mount(
+        m.source.as_deref(),
+        &dest,
+        m.r#type.as_deref(),
+        flags,
+        data.as_deref(),
+    )?;
+
+    if bind_readonly {
+        mount(
+            None::<&str>,
+            &dest,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None::<&str>,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn resolve_dest(new_root: &Path, destination: &str) -> PathBuf {
+    new_root.join(destination.trim_start_matches('/'))
+}
+
+/// Create the mount point if it doesn't already exist: a bind mount whose
+/// source is a regular file needs an empty file as its target, everything
+/// else needs a directory.
+fn prepare_target(dest: &Path, m: &Mount) -> Result<()> {
+    if dest.exists() {
+        return Ok(());
+    }
+
+    let source_is_file = m
+        .source
+        .as_ref()
+        .and_then(|s| metadata(s).ok())
+        .map(|md| md.is_file())
+        .unwrap_or(false);
+
+    if source_is_file {
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
+        }
+        File::create(dest)?;
+    } else {
+        create_dir_all(dest)?;
+    }
+    Ok(())
+}
+
+/// Map the OCI `options` string vector to `mount(2)` flags plus a
+/// comma-joined data string for whatever didn't map to a flag (filesystem
+/// specific options such as `size=`, `mode=`, `uid=`, ...).
+fn parse_options(options: &[String]) -> (MsFlags, Option<String>) {
+    let mut flags = MsFlags::empty();
+    let mut data = vec![];
+
+    for option in options {
+        match option.as_str() {
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "rw" => flags &= !MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "suid" => flags &= !MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "dev" => flags &= !MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "exec" => flags &= !MsFlags::MS_NOEXEC,
+            "bind" => flags |= MsFlags::MS_BIND,
+            "rbind" => flags |= MsFlags::MS_BIND | MsFlags::MS_REC,
+            "remount" => flags |= MsFlags::MS_REMOUNT,
+            "private" => flags |= MsFlags::MS_PRIVATE,
+            "shared" => flags |= MsFlags::MS_SHARED,
+            "slave" => flags |= MsFlags::MS_SLAVE,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "strictatime" => flags |= MsFlags::MS_STRICTATIME,
+            _ => data.push(option.clone()),
+        }
+    }
+
+    let data = if data.is_empty() {
+        None
+    } else {
+        Some(data.join(","))
+    };
+    (flags, data)
+}
@@ -0,0 +1,120 @@
+use anyhow::{bail, Context, Result};
+use libseccomp::{
+    ScmpAction, ScmpArgCompare, ScmpArch, ScmpCompareOp, ScmpFilterContext, ScmpSyscall,
+};
+use serde::{Deserialize, Serialize};
+
+/// `spec.linux.seccomp`, translated into a libseccomp filter and loaded
+/// into the kernel right before `execvp` of the container payload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Seccomp {
+    default_action: String,
+    #[serde(default)]
+    architectures: Vec<String>,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    names: Vec<String>,
+    action: String,
+    #[serde(default)]
+    args: Vec<SyscallArg>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallArg {
+    index: u32,
+    value: u64,
+    value_two: Option<u64>,
+    op: String,
+}
+
+/// Build and load the filter described by `seccomp`. Must run in the
+/// payload process forked from init, after every other setup step and
+/// immediately before `execvp`,
+/// so the filter covers the container payload but none of our own syscalls.
+///
+/// `SCMP_ACT_NOTIFY` is rejected up front rather than silently accepted: it
+/// hands back a listener fd that only means something if some supervisor
+/// is on the other end polling it and resolving notifications, and we have
+/// no such supervisor. A filter that claimed to support it without one
+/// would just leave the notified syscall blocked forever.
+pub fn load(seccomp: &Seccomp) -> Result<()> {
+    let default_action = parse_action(&seccomp.default_action)?;
+    let mut ctx = ScmpFilterContext::new_filter(default_action)
+        .context("failed to create seccomp filter")?;
+
+    for arch in &seccomp.architectures {
+        ctx.add_arch(parse_arch(arch)?)
+            .with_context(|| format!("failed to add seccomp architecture {}", arch))?;
+    }
+
+    for rule in &seccomp.syscalls {
+        let action = parse_action(&rule.action)?;
+        let comparisons = rule
+            .args
+            .iter()
+            .map(parse_arg)
+            .collect::<Result<Vec<_>>>()?;
+
+        for name in &rule.names {
+            let syscall = ScmpSyscall::from_name(name)
+                .with_context(|| format!("unknown syscall {}", name))?;
+            ctx.add_rule_conditional(action, syscall, &comparisons)
+                .with_context(|| format!("failed to add seccomp rule for {}", name))?;
+        }
+    }
+
+    ctx.load().context("failed to load seccomp filter")?;
+    Ok(())
+}
+
+fn parse_action(action: &str) -> Result<ScmpAction> {
+    Ok(match action {
+        "SCMP_ACT_ALLOW" => ScmpAction::Allow,
+        "SCMP_ACT_ERRNO" => ScmpAction::Errno(nix::libc::EPERM),
+        "SCMP_ACT_KILL" => ScmpAction::KillThread,
+        "SCMP_ACT_KILL_PROCESS" => ScmpAction::KillProcess,
+        "SCMP_ACT_TRAP" => ScmpAction::Trap,
+        "SCMP_ACT_LOG" => ScmpAction::Log,
+        "SCMP_ACT_NOTIFY" => bail!(
+            "SCMP_ACT_NOTIFY is not supported: no supervisor is available to consume the notify fd"
+        ),
+        _ => bail!("unsupported seccomp action {}", action),
+    })
+}
+
+fn parse_arch(arch: &str) -> Result<ScmpArch> {
+    Ok(match arch {
+        "SCMP_ARCH_X86_64" => ScmpArch::X8664,
+        "SCMP_ARCH_AARCH64" => ScmpArch::Aarch64,
+        "SCMP_ARCH_X86" => ScmpArch::X86,
+        _ => bail!("unsupported seccomp architecture {}", arch),
+    })
+}
+
+fn parse_op(op: &str, value_two: Option<u64>) -> Result<ScmpCompareOp> {
+    Ok(match op {
+        "SCMP_CMP_NE" => ScmpCompareOp::NotEqual,
+        "SCMP_CMP_LT" => ScmpCompareOp::Less,
+        "SCMP_CMP_LE" => ScmpCompareOp::LessOrEqual,
+        "SCMP_CMP_EQ" => ScmpCompareOp::Equal,
+        "SCMP_CMP_GE" => ScmpCompareOp::GreaterEqual,
+        "SCMP_CMP_GT" => ScmpCompareOp::Greater,
+        "SCMP_CMP_MASKED_EQ" => {
+            let mask = value_two.context("SCMP_CMP_MASKED_EQ requires value_two as the mask")?;
+            ScmpCompareOp::MaskedEqual(mask)
+        }
+        _ => bail!("unsupported seccomp comparison operator {}", op),
+    })
+}
+
+fn parse_arg(arg: &SyscallArg) -> Result<ScmpArgCompare> {
+    let op = parse_op(&arg.op, arg.value_two)?;
+    Ok(ScmpArgCompare::new(arg.index, op, arg.value))
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::fs::{read_to_string, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const STATE_FILE: &str = "state.json";
+const OCI_VERSION: &str = "1.0.2";
+
+/// The lifecycle status of a container, per the OCI Runtime Spec.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Creating,
+    Created,
+    Running,
+    Stopped,
+}
+
+/// The OCI `State` object, persisted as `<root>/<container_id>/state.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    oci_version: String,
+    id: String,
+    status: Status,
+    pid: i32,
+    bundle: PathBuf,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    /// Whether this container's cgroup was set up via the systemd driver
+    /// (vs. the plain filesystem driver), so `delete` can reconstruct the
+    /// right `Cgroup` handle to clean it up without needing the original
+    /// CLI invocation's `--systemd-cgroup` flag.
+    #[serde(default)]
+    systemd_cgroup: bool,
+}
+
+impl State {
+    pub fn new(
+        id: String,
+        bundle: PathBuf,
+        pid: i32,
+        annotations: HashMap<String, String>,
+        systemd_cgroup: bool,
+    ) -> Self {
+        Self {
+            oci_version: OCI_VERSION.to_string(),
+            id,
+            status: Status::Creating,
+            pid,
+            bundle,
+            annotations,
+            systemd_cgroup,
+        }
+    }
+
+    pub fn load(container_dir: &Path) -> Result<Self> {
+        let path = container_dir.join(STATE_FILE);
+        let data = read_to_string(&path).with_context(|| format!("failed to read {:?}", path))?;
+        serde_json::from_str(&data).with_context(|| format!("failed to parse {:?}", path))
+    }
+
+    pub fn save(&self, container_dir: &Path) -> Result<()> {
+        let path = container_dir.join(STATE_FILE);
+        let data = serde_json::to_string_pretty(self)?;
+        write(&path, data).with_context(|| format!("failed to write {:?}", path))
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn bundle(&self) -> &Path {
+        &self.bundle
+    }
+
+    pub fn systemd_cgroup(&self) -> bool {
+        self.systemd_cgroup
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    /// Reconcile `status` with reality: if we think the init process is
+    /// still alive but `/proc/<pid>` says otherwise, the container has
+    /// exited without us hearing about it. Persists the correction.
+    pub fn refresh(&mut self, container_dir: &Path) -> Result<()> {
+        let alive = Path::new(&format!("/proc/{}", self.pid)).exists();
+        if !alive && matches!(self.status, Status::Created | Status::Running) {
+            self.status = Status::Stopped;
+            self.save(container_dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Remove the container's state directory entirely.
+pub fn delete(container_dir: &Path) -> Result<()> {
+    if container_dir.exists() {
+        remove_dir_all(container_dir)
+            .with_context(|| format!("failed to remove {:?}", container_dir))?;
+    }
+    Ok(())
+}
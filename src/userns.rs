@@ -0,0 +1,82 @@
+use std::fs::write;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::{IpcChannel, SyncMessage};
+
+/// A single line of `/proc/<pid>/{uid,gid}_map`: map `size` ids starting at
+/// `container_id` (inside the namespace) to `host_id` (outside it).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct IdMapping {
+    #[serde(rename = "containerID")]
+    pub container_id: u32,
+    #[serde(rename = "hostID")]
+    pub host_id: u32,
+    pub size: u32,
+}
+
+/// Write `uidMappings`/`gidMappings` to the new user namespace owned by
+/// `pid`, once `channel` tells us the child has called
+/// `unshare(CLONE_NEWUSER)`, then release the child to continue.
+pub fn write_id_mappings(
+    pid: i32,
+    uid_mappings: &[IdMapping],
+    gid_mappings: &[IdMapping],
+    channel: &IpcChannel,
+) -> Result<()> {
+    // wait until the child has unshared its user namespace
+    channel.recv()?;
+
+    write_map(pid, "uid", uid_mappings)?;
+
+    // the kernel refuses to apply a gid_map from an unprivileged process
+    // unless setgroups has first been permanently disabled for the target
+    if !Uid::effective().is_root() {
+        write(format!("/proc/{}/setgroups", pid), "deny")
+            .with_context(|| format!("failed to write /proc/{}/setgroups", pid))?;
+    }
+    write_map(pid, "gid", gid_mappings)?;
+
+    channel.send(&SyncMessage::MappingWritten)?;
+    Ok(())
+}
+
+fn write_map(pid: i32, kind: &str, mappings: &[IdMapping]) -> Result<()> {
+    let path = format!("/proc/{}/{}_map", pid, kind);
+    let data = mappings
+        .iter()
+        .map(|m| format!("{} {} {}", m.container_id, m.host_id, m.size))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if write(&path, &data).is_err() {
+        // the direct write failed, most likely because the host ids span a
+        // range we don't own as an unprivileged user; fall back to the
+        // setuid helpers that consult /etc/sub{u,g}id
+        run_newidmap(if kind == "uid" { "newuidmap" } else { "newgidmap" }, pid, mappings)
+            .with_context(|| format!("failed to write {}", path))?;
+    }
+    Ok(())
+}
+
+fn run_newidmap(helper: &str, pid: i32, mappings: &[IdMapping]) -> Result<()> {
+    let mut args = vec![pid.to_string()];
+    for m in mappings {
+        args.push(m.container_id.to_string());
+        args.push(m.host_id.to_string());
+        args.push(m.size.to_string());
+    }
+
+    let status = Command::new(helper)
+        .args(&args)
+        .status()
+        .with_context(|| format!("failed to spawn {}", helper))?;
+
+    if !status.success() {
+        bail!("{} exited with {}", helper, status);
+    }
+    Ok(())
+}